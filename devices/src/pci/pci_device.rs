@@ -2,12 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fmt::{self, Display};
+use std::io;
 use std::os::unix::io::RawFd;
 
+use acpi_tables::sdt::SDT;
 use kvm::Datamatch;
 use resources::{Error as SystemAllocatorFaliure, SystemAllocator};
 use sys_util::EventFd;
+use vm_control::IpcMemoryMapper;
 
 use crate::pci::pci_configuration;
 use crate::pci::{PciAddress, PciInterruptPin};
@@ -24,6 +29,10 @@ pub enum Error {
     /// Create cras client failed.
     #[cfg(feature = "audio")]
     CreateCrasClientFailed(libcras::Error),
+    /// No free slot was available on the given bus for a hot-plugged device.
+    NoFreeSlot(u8),
+    /// The address passed to a hotplug operation has no device registered at it.
+    NoSuchDevice(PciAddress),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -43,10 +52,390 @@ impl Display for Error {
             IoRegistrationFailed(addr, e) => {
                 write!(f, "failed to register an IO BAR, addr={} err={}", addr, e)
             }
+            NoFreeSlot(bus) => write!(f, "no free hotplug slot on bus {}", bus),
+            NoSuchDevice(address) => write!(f, "no device registered at {}", address),
         }
     }
 }
 
+/// Size in bytes of the MSI-X capability structure found in PCI config space (Capability ID,
+/// next pointer, Message Control, and the Table/PBA BIR+offset dwords).
+pub const MSIX_CAP_LEN: usize = 12;
+
+/// Size in bytes of a single MSI-X vector table entry.
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+const MSIX_TABLE_ENTRY_BYTES: usize = MSIX_TABLE_ENTRY_SIZE as usize;
+
+const MSIX_CTRL_ENABLE: u16 = 0x8000;
+const MSIX_CTRL_FUNCTION_MASK: u16 = 0x4000;
+const MSIX_CTRL_TABLE_SIZE_MASK: u16 = 0x07ff;
+
+/// One entry of the MSI-X vector table: the message to send and its per-vector mask bit.
+#[derive(Clone, Copy, Default)]
+struct MsixTableEntry {
+    msg_addr_lo: u32,
+    msg_addr_hi: u32,
+    msg_data: u32,
+    vector_ctrl: u32,
+}
+
+impl MsixTableEntry {
+    fn masked(&self) -> bool {
+        self.vector_ctrl & 0x1 != 0
+    }
+
+    fn to_le_bytes(self) -> [u8; MSIX_TABLE_ENTRY_BYTES] {
+        let mut bytes = [0u8; MSIX_TABLE_ENTRY_BYTES];
+        bytes[0..4].copy_from_slice(&self.msg_addr_lo.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.msg_addr_hi.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.msg_data.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.vector_ctrl.to_le_bytes());
+        bytes
+    }
+
+    fn from_le_bytes(bytes: [u8; MSIX_TABLE_ENTRY_BYTES]) -> Self {
+        MsixTableEntry {
+            msg_addr_lo: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            msg_addr_hi: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            msg_data: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            vector_ctrl: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// The selected table entry for an interrupt a `PciDevice` wants to raise.
+pub struct InterruptParameters {
+    /// Index of the vector that is firing.
+    pub vector: u16,
+    /// 64-bit message address programmed by the guest into the table entry.
+    pub msg_addr: u64,
+    /// 32-bit message data programmed by the guest into the table entry.
+    pub msg_data: u32,
+    /// The table entry's mask bit at delivery time.
+    pub masked: bool,
+}
+
+/// Callback a `PciDevice` invokes to deliver an MSI-X interrupt, wired up by the VMM to an
+/// irqfd for the selected vector.
+pub type InterruptDelivery = Box<dyn Fn(InterruptParameters) -> io::Result<()> + Send + Sync>;
+
+/// Tracks a device's MSI-X capability state: the vector table, the pending bit array, and
+/// whether vectors are currently masked. Devices that support MSI-X own one of these and
+/// route BAR accesses to the table/PBA regions through it.
+pub struct MsixConfig {
+    table: Vec<MsixTableEntry>,
+    pba: Vec<u64>,
+    enabled: bool,
+    function_mask: bool,
+    delivery: Option<InterruptDelivery>,
+}
+
+impl MsixConfig {
+    pub fn new(num_vectors: u16) -> Self {
+        let pba_words = (num_vectors as usize + 63) / 64;
+        MsixConfig {
+            table: vec![MsixTableEntry::default(); num_vectors as usize],
+            pba: vec![0; pba_words],
+            enabled: false,
+            function_mask: false,
+            delivery: None,
+        }
+    }
+
+    /// Sets the callback used to deliver unmasked interrupts.
+    pub fn set_delivery(&mut self, delivery: InterruptDelivery) {
+        self.delivery = Some(delivery);
+    }
+
+    fn set_pba_bit(&mut self, vector: u16, set: bool) {
+        let word = vector as usize / 64;
+        let bit = vector as usize % 64;
+        if set {
+            self.pba[word] |= 1 << bit;
+        } else {
+            self.pba[word] &= !(1 << bit);
+        }
+    }
+
+    fn pba_bit(&self, vector: u16) -> bool {
+        let word = vector as usize / 64;
+        let bit = vector as usize % 64;
+        self.pba[word] & (1 << bit) != 0
+    }
+
+    fn deliver(&self, vector: u16) -> io::Result<()> {
+        let entry = &self.table[vector as usize];
+        match &self.delivery {
+            Some(delivery) => delivery(InterruptParameters {
+                vector,
+                msg_addr: (u64::from(entry.msg_addr_hi) << 32) | u64::from(entry.msg_addr_lo),
+                msg_data: entry.msg_data,
+                masked: entry.masked(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Raises `vector`. If the vector (or the whole function) is currently masked, the
+    /// interrupt is recorded in the PBA instead and delivered once it is unmasked.
+    pub fn trigger(&mut self, vector: u16) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        if self.function_mask || self.table[vector as usize].masked() {
+            self.set_pba_bit(vector, true);
+            return Ok(());
+        }
+
+        self.deliver(vector)
+    }
+
+    fn unmask_vector(&mut self, vector: u16) -> io::Result<()> {
+        if !self.enabled || self.function_mask || self.table[vector as usize].masked() {
+            return Ok(());
+        }
+        if self.pba_bit(vector) {
+            self.set_pba_bit(vector, false);
+            self.deliver(vector)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a read from the MSI-X vector table, which is mapped into one of the device's
+    /// BARs. `data` may be any legal MMIO access width (1-8 bytes), including an aligned QWORD
+    /// access that spans two of the entry's fields; out-of-range offset/length combinations are
+    /// ignored rather than causing a panic.
+    pub fn read_table(&self, offset: u64, data: &mut [u8]) {
+        let index = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+        let entry = match self.table.get(index) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let entry_bytes = entry.to_le_bytes();
+
+        let start = (offset % MSIX_TABLE_ENTRY_SIZE) as usize;
+        let end = match start.checked_add(data.len()) {
+            Some(end) if end <= entry_bytes.len() => end,
+            _ => return,
+        };
+        data.copy_from_slice(&entry_bytes[start..end]);
+    }
+
+    /// Handles a write to the MSI-X vector table, which is mapped into one of the device's
+    /// BARs. `data` may be any legal MMIO access width (1-8 bytes); re-evaluates the pending bit
+    /// when a vector's mask bit is cleared. Out-of-range offset/length combinations are ignored
+    /// rather than causing a panic.
+    pub fn write_table(&mut self, offset: u64, data: &[u8]) {
+        let index = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+        let entry = match self.table.get(index) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let start = (offset % MSIX_TABLE_ENTRY_SIZE) as usize;
+        let mut entry_bytes = entry.to_le_bytes();
+        let end = match start.checked_add(data.len()) {
+            Some(end) if end <= entry_bytes.len() => end,
+            _ => return,
+        };
+        entry_bytes[start..end].copy_from_slice(data);
+
+        let was_masked = self.table[index].masked();
+        self.table[index] = MsixTableEntry::from_le_bytes(entry_bytes);
+
+        if was_masked && !self.table[index].masked() {
+            let _ = self.unmask_vector(index as u16);
+        }
+    }
+
+    /// Handles a read from the pending bit array, which is mapped into one of the device's
+    /// BARs. Out-of-range offset/length combinations are ignored rather than causing a panic.
+    pub fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        let index = (offset / 8) as usize;
+        let word = match self.pba.get(index) {
+            Some(word) => word,
+            None => return,
+        };
+        let bytes = word.to_le_bytes();
+
+        let start = (offset % 8) as usize;
+        let end = match start.checked_add(data.len()) {
+            Some(end) if end <= bytes.len() => end,
+            _ => return,
+        };
+        data.copy_from_slice(&bytes[start..end]);
+    }
+
+    /// Handles a write to the Message Control register (the upper 16 bits of the capability's
+    /// first dword), updating the enable/function-mask state. Unmasking the function fires
+    /// any interrupts that became pending while it was masked.
+    pub fn write_msg_control(&mut self, control: u16) {
+        self.enabled = control & MSIX_CTRL_ENABLE != 0;
+        let was_function_masked = self.function_mask;
+        self.function_mask = control & MSIX_CTRL_FUNCTION_MASK != 0;
+
+        if was_function_masked && !self.function_mask {
+            for vector in 0..self.table.len() as u16 {
+                let _ = self.unmask_vector(vector);
+            }
+        }
+    }
+
+    /// Builds the static portion of the MSI-X capability structure, to be spliced into the
+    /// device's config space capability list. `table_bar`/`pba_bar` are the BAR indices (BIR)
+    /// that hold the vector table and pending bit array, at the given byte offsets.
+    pub fn new_msix_cap(
+        num_vectors: u16,
+        table_bar: u8,
+        table_offset: u32,
+        pba_bar: u8,
+        pba_offset: u32,
+    ) -> [u8; MSIX_CAP_LEN] {
+        let mut cap = [0u8; MSIX_CAP_LEN];
+        cap[0] = 0x11; // MSI-X Capability ID.
+        let control = num_vectors.saturating_sub(1) & MSIX_CTRL_TABLE_SIZE_MASK;
+        cap[2..4].copy_from_slice(&control.to_le_bytes());
+        cap[4..8].copy_from_slice(&(table_offset | u32::from(table_bar & 0x7)).to_le_bytes());
+        cap[8..12].copy_from_slice(&(pba_offset | u32::from(pba_bar & 0x7)).to_le_bytes());
+        cap
+    }
+}
+
+/// Type of address space a PCI BAR maps into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PciBarRegionType {
+    /// A 16-bit I/O port BAR.
+    IoSpace,
+    /// A 32-bit memory BAR.
+    Memory32,
+    /// A 64-bit memory BAR. Occupies this BAR register and the following one.
+    Memory64,
+}
+
+/// Describes one BAR a device has been allocated: its index, location, size, and how the
+/// corresponding config-space register(s) should be programmed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PciBarConfiguration {
+    bar_index: usize,
+    addr: u64,
+    size: u64,
+    region_type: PciBarRegionType,
+    prefetchable: bool,
+}
+
+impl PciBarConfiguration {
+    pub fn new(
+        bar_index: usize,
+        size: u64,
+        region_type: PciBarRegionType,
+        prefetchable: bool,
+    ) -> Self {
+        PciBarConfiguration {
+            bar_index,
+            addr: 0,
+            size,
+            region_type,
+            prefetchable,
+        }
+    }
+
+    /// Returns a copy of this configuration with its base address set to `addr`, as returned
+    /// by the `SystemAllocator`.
+    pub fn set_address(mut self, addr: u64) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn bar_index(&self) -> usize {
+        self.bar_index
+    }
+
+    pub fn address(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn region_type(&self) -> PciBarRegionType {
+        self.region_type
+    }
+
+    pub fn is_prefetchable(&self) -> bool {
+        self.prefetchable
+    }
+
+    pub fn is_memory(&self) -> bool {
+        self.region_type != PciBarRegionType::IoSpace
+    }
+
+    pub fn is_64bit_memory(&self) -> bool {
+        self.region_type == PciBarRegionType::Memory64
+    }
+
+    /// Returns the raw config register value(s) to program into the BAR's register pair, with
+    /// the region-type and prefetchable bits set. A 64-bit memory BAR also returns the value
+    /// for the following register, which holds the upper 32 bits of the address.
+    pub fn config_register_value(&self) -> (u32, Option<u32>) {
+        let low = match self.region_type {
+            PciBarRegionType::IoSpace => (self.addr as u32 & 0xffff_fffc) | 0x1,
+            PciBarRegionType::Memory32 => {
+                (self.addr as u32 & 0xffff_fff0) | if self.prefetchable { 0x8 } else { 0 }
+            }
+            PciBarRegionType::Memory64 => {
+                (self.addr as u32 & 0xffff_fff0) | 0x4 | if self.prefetchable { 0x8 } else { 0 }
+            }
+        };
+        let high = if self.region_type == PciBarRegionType::Memory64 {
+            Some((self.addr >> 32) as u32)
+        } else {
+            None
+        };
+        (low, high)
+    }
+}
+
+/// Describes a BAR whose base address changed as the result of a configuration-space write,
+/// so the VMM can move the device's region on the appropriate bus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BarRemapInfo {
+    /// Index of the BAR register that was reprogrammed.
+    pub bar_index: usize,
+    /// Type of address space the BAR maps into.
+    pub region_type: PciBarRegionType,
+    /// The BAR's base address before this write.
+    pub old_base: u64,
+    /// The BAR's base address after this write.
+    pub new_base: u64,
+    /// Size of the region in bytes.
+    pub size: u64,
+}
+
+/// Reports a guest write to the command register that changed whether the device's memory
+/// and/or IO space BARs are decoded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandEnableChange {
+    /// Whether the memory space enable bit changed.
+    pub mem_space_changed: bool,
+    /// The memory space enable bit's new value.
+    pub mem_space_enabled: bool,
+    /// Whether the IO space enable bit changed.
+    pub io_space_changed: bool,
+    /// The IO space enable bit's new value.
+    pub io_space_enabled: bool,
+}
+
+/// Result of a configuration-space write, reported back to the bus so it can react to BAR
+/// reprogramming or command-register enable/disable transitions. The default (no BAR remap,
+/// no command change) means the write had no effect the bus needs to act on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigWriteResult {
+    pub bar_remap: Option<BarRemapInfo>,
+    pub command_change: Option<CommandEnableChange>,
+}
+
 pub trait PciDevice: Send {
     /// Returns a label suitable for debug output.
     fn debug_label(&self) -> String;
@@ -66,22 +455,25 @@ pub trait PciDevice: Send {
         _irq_pin: PciInterruptPin,
     ) {
     }
-    /// Allocates the needed IO BAR space using the `allocate` function which takes a size and
-    /// returns an address. Returns a Vec of (address, length) tuples.
-    fn allocate_io_bars(&mut self, _resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
-        Ok(Vec::new())
-    }
-
-    /// Allocates the needed device BAR space. Returns a Vec of (address, length) tuples.
-    /// Unlike MMIO BARs (see allocate_io_bars), device BARs are not expected to incur VM exits
-    /// - these BARs represent normal memory.
-    fn allocate_device_bars(
+    /// Assigns an MSI-X delivery callback to this device. The device stores it and invokes it
+    /// for each vector it raises, or queues the interrupt in its `MsixConfig`'s PBA if the
+    /// vector (or the function) is currently masked.
+    fn assign_msix(&mut self, _delivery: InterruptDelivery) {}
+    /// Allocates the needed BAR space using `resources`, returning one `PciBarConfiguration`
+    /// per BAR the device uses (I/O, 32-bit memory, or 64-bit memory).
+    fn allocate_bars(
         &mut self,
         _resources: &mut SystemAllocator,
-    ) -> Result<Vec<(u64, u64)>> {
+    ) -> Result<Vec<PciBarConfiguration>> {
         Ok(Vec::new())
     }
 
+    /// Returns the configuration of the BAR at `bar_index`, if the device has allocated one
+    /// there, so the bus can look up its location/size/type when handling reprogramming.
+    fn get_bar_configuration(&self, _bar_index: usize) -> Option<PciBarConfiguration> {
+        None
+    }
+
     /// Register any capabilties specified by the device.
     fn register_device_capabilities(&mut self) -> Result<()> {
         Ok(())
@@ -101,7 +493,15 @@ pub trait PciDevice: Send {
     /// * `reg_idx` - PCI register index (in units of 4 bytes).
     /// * `offset`  - byte offset within 4-byte register.
     /// * `data`    - The data to write.
-    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]);
+    ///
+    /// Returns a `ConfigWriteResult` describing any BAR reprogramming or command-register
+    /// enable/disable transition the write caused, so the bus can remap the device's regions.
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> ConfigWriteResult;
 
     /// Reads from a BAR region mapped in to the device.
     /// * `addr` - The guest address inside the BAR.
@@ -113,6 +513,206 @@ pub trait PciDevice: Send {
     fn write_bar(&mut self, addr: u64, data: &[u8]);
     /// Invoked when the device is sandboxed.
     fn on_device_sandboxed(&mut self) {}
+
+    /// Invoked when the device is being hot-removed. Implementations should tear down any
+    /// BAR/IRQ/ioeventfd registrations they made with the running VM; the hotplug subsystem
+    /// frees the `SystemAllocator` resources afterwards.
+    fn destroy_device(&mut self) {}
+
+    /// Lets the device append or patch ACPI System Description Tables during machine setup,
+    /// e.g. to add a `_DSM` method or a device-specific SSDT fragment. The default keeps
+    /// `sdts` unchanged.
+    fn generate_acpi(&mut self, sdts: Vec<SDT>) -> Option<Vec<SDT>> {
+        Some(sdts)
+    }
+
+    /// Returns AML bytecode to be placed under this device's slot object in the DSDT, if the
+    /// device needs to expose additional methods or properties there.
+    fn generate_acpi_aml(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Hands the device an IOMMU mapper it should use to translate guest IOVAs and program
+    /// its DMA translations, instead of assuming identity access to guest memory. Devices
+    /// placed behind an emulated IOMMU (VFIO passthrough, virtio-iommu, restricted DMA)
+    /// override this; the file descriptors the mapper needs kept open across jailing must be
+    /// folded into `keep_fds`. The default is a no-op for devices with unrestricted DMA.
+    fn set_iommu(&mut self, _mapper: IpcMemoryMapper) {}
+}
+
+/// Presence/attention state a `HotPlugBus` asserts for one of its slots, surfaced to the
+/// guest as a PCIe hot-plug event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HotPlugState {
+    /// A device occupies the slot; the guest should be notified to enumerate it.
+    Present,
+    /// The slot is empty.
+    Absent,
+}
+
+/// A PCI(e) bus capable of hosting hot-pluggable devices. Implementations own the slots'
+/// presence/attention logic and notify the guest when a device is attached or removed.
+pub trait HotPlugBus: Send {
+    /// Asserts `slot`'s presence/attention state, raising the corresponding hot-add or
+    /// hot-removal event to the guest.
+    fn set_slot_state(&mut self, slot: PciAddress, state: HotPlugState) -> Result<()>;
+    /// Returns whether `slot` currently has a device attached, from the bus's point of view.
+    fn is_occupied(&self, slot: PciAddress) -> bool;
+}
+
+/// Releases the resources backing a previously-allocated BAR, by base address. `SystemAllocator`
+/// implements this directly; the indirection only exists so `PciHotPlugManager::remove_device`'s
+/// freeing step can be exercised against a test double instead of a real `SystemAllocator`.
+pub trait BarResourceReleaser {
+    fn free(&mut self, addr: u64);
+}
+
+impl BarResourceReleaser for SystemAllocator {
+    fn free(&mut self, addr: u64) {
+        SystemAllocator::free(self, addr)
+    }
+}
+
+/// A hot-plug add/remove request delivered over the VM control channel.
+pub enum HotPlugRequest {
+    /// Attach a device to `bus`; the manager allocates it a free slot. `msix_delivery` is
+    /// wired up via `assign_msix` when given; a device that needs MSI-X (e.g. modern virtio
+    /// or passthrough devices) must supply one here, since there is no other opportunity to
+    /// do so after the device is attached.
+    Add {
+        bus: u8,
+        msix_delivery: Option<InterruptDelivery>,
+    },
+    /// Detach the device at `address`.
+    Remove { address: PciAddress },
+}
+
+/// Coordinates PCI hot-plug: allocating a fresh `PciAddress` for a newly attached device,
+/// wiring it up exactly like a boot-time device would be (BARs, IRQ, capabilities),
+/// registering it on the bus, and reversing all of that - including freeing its
+/// `SystemAllocator` resources - on removal. Also the entry point the VM control channel
+/// dispatches `HotPlugRequest`s to.
+pub struct PciHotPlugManager {
+    bus: Box<dyn HotPlugBus>,
+    slots: HashMap<PciAddress, Vec<PciBarConfiguration>>,
+}
+
+impl PciHotPlugManager {
+    pub fn new(bus: Box<dyn HotPlugBus>) -> Self {
+        PciHotPlugManager {
+            bus,
+            slots: HashMap::new(),
+        }
+    }
+
+    /// Finds the first device slot on `bus` that neither this manager nor the underlying
+    /// `HotPlugBus` already considers occupied.
+    fn allocate_slot(&self, bus: u8) -> Result<PciAddress> {
+        for dev in 1..32 {
+            let candidate = PciAddress::new(0, bus, dev, 0);
+            if !self.slots.contains_key(&candidate) && !self.bus.is_occupied(candidate) {
+                return Ok(candidate);
+            }
+        }
+        Err(Error::NoFreeSlot(bus))
+    }
+
+    /// Attaches `device` to `bus`: allocates it a free `PciAddress`, allocates its BARs out of
+    /// `resources`, assigns its IRQ and (if `msix_delivery` is given) its MSI-X delivery
+    /// callback, registers its capabilities, then tells the bus to raise the slot's hot-add
+    /// event. Returns the address the device was attached at.
+    ///
+    /// Hot-added devices only get MSI-X if `msix_delivery` is supplied here: there is no other
+    /// opportunity to call `assign_msix` once the device is attached, so callers hot-plugging a
+    /// device that needs MSI-X (e.g. a modern virtio or passthrough device) must pass one.
+    ///
+    /// If capability registration or the bus's hot-add notification fails after BARs were
+    /// already allocated, the BARs are freed and `destroy_device` is invoked before the error
+    /// is returned, so a failed attach never leaves resources allocated or the device
+    /// half-attached and untracked.
+    pub fn add_device(
+        &mut self,
+        bus: u8,
+        device: &mut dyn PciDevice,
+        resources: &mut SystemAllocator,
+        irq_evt: EventFd,
+        irq_resample_evt: EventFd,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+        msix_delivery: Option<InterruptDelivery>,
+    ) -> Result<PciAddress> {
+        let address = self.allocate_slot(bus)?;
+
+        device.assign_address(address);
+        let bars = device.allocate_bars(resources)?;
+        device.assign_irq(irq_evt, irq_resample_evt, irq_num, irq_pin);
+        if let Some(delivery) = msix_delivery {
+            device.assign_msix(delivery);
+        }
+
+        if let Err(e) = device
+            .register_device_capabilities()
+            .and_then(|_| self.bus.set_slot_state(address, HotPlugState::Present))
+        {
+            device.destroy_device();
+            for bar in &bars {
+                resources.free(bar.address());
+            }
+            return Err(e);
+        }
+
+        self.slots.insert(address, bars);
+        Ok(address)
+    }
+
+    /// Removes the device at `address`: tears down its BAR/IRQ/ioeventfd registrations, frees
+    /// the `SystemAllocator` resources it was allocated, and tells the bus to raise the slot's
+    /// hot-removal event.
+    pub fn remove_device(
+        &mut self,
+        address: PciAddress,
+        device: &mut dyn PciDevice,
+        resources: &mut dyn BarResourceReleaser,
+    ) -> Result<()> {
+        let bars = self.slots.remove(&address).ok_or(Error::NoSuchDevice(address))?;
+
+        device.destroy_device();
+        for bar in bars {
+            resources.free(bar.address());
+        }
+
+        self.bus.set_slot_state(address, HotPlugState::Absent)
+    }
+
+    /// Entry point for the VM control channel: dispatches a `HotPlugRequest` received from an
+    /// external command to the corresponding add/remove operation, identifying the target by
+    /// its PCI bus path.
+    pub fn handle_request(
+        &mut self,
+        request: HotPlugRequest,
+        device: &mut dyn PciDevice,
+        resources: &mut SystemAllocator,
+        irq_evt: EventFd,
+        irq_resample_evt: EventFd,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) -> Result<()> {
+        match request {
+            HotPlugRequest::Add { bus, msix_delivery } => self
+                .add_device(
+                    bus,
+                    device,
+                    resources,
+                    irq_evt,
+                    irq_resample_evt,
+                    irq_num,
+                    irq_pin,
+                    msix_delivery,
+                )
+                .map(|_| ()),
+            HotPlugRequest::Remove { address } => self.remove_device(address, device, resources),
+        }
+    }
 }
 
 impl<T: PciDevice> BusDevice for T {
@@ -128,9 +728,14 @@ impl<T: PciDevice> BusDevice for T {
         self.write_bar(offset, data)
     }
 
-    fn config_register_write(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+    fn config_register_write(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> ConfigWriteResult {
         if offset as usize + data.len() > 4 {
-            return;
+            return ConfigWriteResult::default();
         }
 
         self.write_config_register(reg_idx, offset, data)
@@ -165,11 +770,17 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     ) {
         (**self).assign_irq(irq_evt, irq_resample_evt, irq_num, irq_pin)
     }
-    fn allocate_io_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
-        (**self).allocate_io_bars(resources)
+    fn assign_msix(&mut self, delivery: InterruptDelivery) {
+        (**self).assign_msix(delivery)
+    }
+    fn allocate_bars(
+        &mut self,
+        resources: &mut SystemAllocator,
+    ) -> Result<Vec<PciBarConfiguration>> {
+        (**self).allocate_bars(resources)
     }
-    fn allocate_device_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
-        (**self).allocate_device_bars(resources)
+    fn get_bar_configuration(&self, bar_index: usize) -> Option<PciBarConfiguration> {
+        (**self).get_bar_configuration(bar_index)
     }
     fn register_device_capabilities(&mut self) -> Result<()> {
         (**self).register_device_capabilities()
@@ -180,7 +791,12 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn read_config_register(&self, reg_idx: usize) -> u32 {
         (**self).read_config_register(reg_idx)
     }
-    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> ConfigWriteResult {
         (**self).write_config_register(reg_idx, offset, data)
     }
     fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
@@ -193,4 +809,270 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn on_device_sandboxed(&mut self) {
         (**self).on_device_sandboxed()
     }
+    fn destroy_device(&mut self) {
+        (**self).destroy_device()
+    }
+    fn generate_acpi(&mut self, sdts: Vec<SDT>) -> Option<Vec<SDT>> {
+        (**self).generate_acpi(sdts)
+    }
+    fn generate_acpi_aml(&self) -> Option<Vec<u8>> {
+        (**self).generate_acpi_aml()
+    }
+    fn set_iommu(&mut self, mapper: IpcMemoryMapper) {
+        (**self).set_iommu(mapper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn recording_delivery() -> (InterruptDelivery, Arc<Mutex<Vec<u16>>>) {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let recorder = delivered.clone();
+        let delivery: InterruptDelivery = Box::new(move |params| {
+            recorder.lock().unwrap().push(params.vector);
+            Ok(())
+        });
+        (delivery, delivered)
+    }
+
+    #[test]
+    fn trigger_delivers_when_unmasked() {
+        let mut msix = MsixConfig::new(2);
+        let (delivery, delivered) = recording_delivery();
+        msix.set_delivery(delivery);
+        msix.write_msg_control(MSIX_CTRL_ENABLE);
+
+        msix.trigger(0).unwrap();
+
+        assert_eq!(*delivered.lock().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn trigger_ignored_while_disabled() {
+        let mut msix = MsixConfig::new(1);
+        let (delivery, delivered) = recording_delivery();
+        msix.set_delivery(delivery);
+
+        msix.trigger(0).unwrap();
+
+        assert!(delivered.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn masked_vector_queues_in_pba_then_delivers_on_unmask() {
+        let mut msix = MsixConfig::new(2);
+        let (delivery, delivered) = recording_delivery();
+        msix.set_delivery(delivery);
+        msix.write_msg_control(MSIX_CTRL_ENABLE);
+
+        // Mask vector 0 by setting its vector_ctrl mask bit.
+        msix.write_table(12, &1u32.to_le_bytes());
+        msix.trigger(0).unwrap();
+        assert!(delivered.lock().unwrap().is_empty());
+
+        let mut pba = [0u8; 8];
+        msix.read_pba(0, &mut pba);
+        assert_eq!(u64::from_le_bytes(pba) & 0x1, 1);
+
+        // Clearing the mask bit should deliver the interrupt that was pending.
+        msix.write_table(12, &0u32.to_le_bytes());
+        assert_eq!(*delivered.lock().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn function_mask_defers_all_vectors_until_cleared() {
+        let mut msix = MsixConfig::new(2);
+        let (delivery, delivered) = recording_delivery();
+        msix.set_delivery(delivery);
+        msix.write_msg_control(MSIX_CTRL_ENABLE | MSIX_CTRL_FUNCTION_MASK);
+
+        msix.trigger(0).unwrap();
+        msix.trigger(1).unwrap();
+        assert!(delivered.lock().unwrap().is_empty());
+
+        msix.write_msg_control(MSIX_CTRL_ENABLE);
+        let mut got = delivered.lock().unwrap().clone();
+        got.sort_unstable();
+        assert_eq!(got, vec![0, 1]);
+    }
+
+    #[test]
+    fn table_access_widths_round_trip_without_panicking() {
+        let mut msix = MsixConfig::new(1);
+
+        // A QWORD access spans msg_addr_lo and msg_addr_hi (offsets 0..8).
+        let addr: u64 = 0x1234_5678_9abc_def0;
+        msix.write_table(0, &addr.to_le_bytes());
+        let mut readback = [0u8; 8];
+        msix.read_table(0, &mut readback);
+        assert_eq!(u64::from_le_bytes(readback), addr);
+
+        // An out-of-range offset/length combination must be ignored, not panic.
+        let mut oob = [0u8; 8];
+        msix.read_table(12, &mut oob);
+        msix.write_table(12, &oob);
+    }
+
+    #[test]
+    fn pba_access_rejects_out_of_range_length_without_panicking() {
+        let msix = MsixConfig::new(1);
+        let mut data = [0u8; 8];
+        msix.read_pba(4, &mut data);
+    }
+
+    #[test]
+    fn io_space_bar_sets_io_decode_bit() {
+        let bar = PciBarConfiguration::new(0, 0x100, PciBarRegionType::IoSpace, false)
+            .set_address(0xc000);
+        let (low, high) = bar.config_register_value();
+        assert_eq!(low & 0x1, 0x1);
+        assert_eq!(low & !0x3, 0xc000);
+        assert!(high.is_none());
+    }
+
+    #[test]
+    fn memory32_prefetchable_sets_type_and_prefetch_bits() {
+        let bar = PciBarConfiguration::new(1, 0x1000, PciBarRegionType::Memory32, true)
+            .set_address(0xfebf_0000);
+        let (low, high) = bar.config_register_value();
+        assert_eq!(low & 0x1, 0);
+        assert_eq!(low & 0x6, 0);
+        assert_eq!(low & 0x8, 0x8);
+        assert_eq!(low & !0xf, 0xfebf_0000);
+        assert!(high.is_none());
+    }
+
+    #[test]
+    fn memory64_bar_splits_address_across_two_registers() {
+        let addr: u64 = 0x1_2345_6780;
+        let bar = PciBarConfiguration::new(2, 0x10000, PciBarRegionType::Memory64, false)
+            .set_address(addr);
+        let (low, high) = bar.config_register_value();
+        assert_eq!(low & 0x4, 0x4);
+        assert_eq!(
+            u64::from(low & !0xf) | (u64::from(high.unwrap()) << 32),
+            addr
+        );
+    }
+
+    #[derive(Default)]
+    struct FakeHotPlugBus {
+        states: HashMap<PciAddress, HotPlugState>,
+    }
+
+    impl HotPlugBus for FakeHotPlugBus {
+        fn set_slot_state(&mut self, slot: PciAddress, state: HotPlugState) -> Result<()> {
+            self.states.insert(slot, state);
+            Ok(())
+        }
+
+        fn is_occupied(&self, slot: PciAddress) -> bool {
+            self.states.get(&slot) == Some(&HotPlugState::Present)
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeResourceReleaser {
+        freed: Vec<u64>,
+    }
+
+    impl BarResourceReleaser for FakeResourceReleaser {
+        fn free(&mut self, addr: u64) {
+            self.freed.push(addr);
+        }
+    }
+
+    struct FakeDevice;
+
+    impl PciDevice for FakeDevice {
+        fn debug_label(&self) -> String {
+            "fake".to_string()
+        }
+        fn keep_fds(&self) -> Vec<RawFd> {
+            Vec::new()
+        }
+        fn read_config_register(&self, _reg_idx: usize) -> u32 {
+            0
+        }
+        fn write_config_register(
+            &mut self,
+            _reg_idx: usize,
+            _offset: u64,
+            _data: &[u8],
+        ) -> ConfigWriteResult {
+            ConfigWriteResult::default()
+        }
+        fn read_bar(&mut self, _addr: u64, _data: &mut [u8]) {}
+        fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
+    }
+
+    fn fake_bar(index: usize, addr: u64) -> PciBarConfiguration {
+        PciBarConfiguration::new(index, 0x1000, PciBarRegionType::Memory32, false).set_address(addr)
+    }
+
+    #[test]
+    fn allocate_slot_skips_occupied_and_reuses_after_remove() {
+        let mut manager = PciHotPlugManager::new(Box::new(FakeHotPlugBus::default()));
+        let first = manager.allocate_slot(0).unwrap();
+        manager.slots.insert(first, vec![fake_bar(0, 0x1000)]);
+
+        let second = manager.allocate_slot(0).unwrap();
+        assert_ne!(first, second);
+
+        manager.slots.remove(&first);
+        assert_eq!(manager.allocate_slot(0).unwrap(), first);
+    }
+
+    #[test]
+    fn allocate_slot_fails_when_bus_is_full() {
+        let mut manager = PciHotPlugManager::new(Box::new(FakeHotPlugBus::default()));
+        for dev in 1..32 {
+            manager
+                .slots
+                .insert(PciAddress::new(0, 0, dev, 0), Vec::new());
+        }
+
+        match manager.allocate_slot(0) {
+            Err(Error::NoFreeSlot(0)) => {}
+            other => panic!("expected NoFreeSlot(0), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn remove_device_reports_no_such_device_on_double_remove() {
+        let mut manager = PciHotPlugManager::new(Box::new(FakeHotPlugBus::default()));
+        let address = PciAddress::new(0, 0, 1, 0);
+        let mut device = FakeDevice;
+        let mut releaser = FakeResourceReleaser::default();
+
+        manager.slots.insert(address, vec![fake_bar(0, 0x1000)]);
+        manager
+            .remove_device(address, &mut device, &mut releaser)
+            .unwrap();
+
+        match manager.remove_device(address, &mut device, &mut releaser) {
+            Err(Error::NoSuchDevice(a)) => assert_eq!(a, address),
+            other => panic!("expected NoSuchDevice({:?}), got {:?}", address, other),
+        }
+    }
+
+    #[test]
+    fn remove_device_frees_every_allocated_bar() {
+        let mut manager = PciHotPlugManager::new(Box::new(FakeHotPlugBus::default()));
+        let address = PciAddress::new(0, 0, 1, 0);
+        let mut device = FakeDevice;
+        let mut releaser = FakeResourceReleaser::default();
+
+        manager
+            .slots
+            .insert(address, vec![fake_bar(0, 0x1000), fake_bar(1, 0x2000)]);
+        manager
+            .remove_device(address, &mut device, &mut releaser)
+            .unwrap();
+
+        assert_eq!(releaser.freed, vec![0x1000, 0x2000]);
+    }
 }